@@ -0,0 +1,59 @@
+//! Simple exponential backoff used by the reconnect supervisor.
+
+use std::time::Duration;
+
+pub struct Backoff {
+    current: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            current: initial,
+            max,
+        }
+    }
+
+    /// Returns the delay to wait before the next attempt, then doubles it
+    /// (capped at `max`) for the attempt after that.
+    pub fn next(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    /// Resets the backoff after a successful connection.
+    pub fn reset(&mut self, initial: Duration) {
+        self.current = initial;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(5 * 60))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(4));
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+        assert_eq!(backoff.next(), Duration::from_secs(2));
+        assert_eq!(backoff.next(), Duration::from_secs(4));
+        assert_eq!(backoff.next(), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn reset_restores_initial_delay() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(4));
+        backoff.next();
+        backoff.next();
+        backoff.reset(Duration::from_secs(1));
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,139 @@
+//! Persistent on-disk cache of already-moved UIDs and a durable queue of
+//! pending Pushover notifications. This gives at-least-once move and
+//! notification semantics: a crash between `uid_mv` and the next loop, or a
+//! dropped Pushover send, is recovered by replaying the cache on restart
+//! instead of silently losing the work.
+
+use rusqlite::{Connection, OptionalExtension, params};
+use std::path::Path;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("SQLite: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS moved (
+    account TEXT NOT NULL,
+    uidvalidity INTEGER NOT NULL,
+    uid INTEGER NOT NULL,
+    PRIMARY KEY (account, uidvalidity, uid)
+);
+CREATE TABLE IF NOT EXISTS pending_notifications (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    account TEXT NOT NULL,
+    mailboxes TEXT NOT NULL
+);";
+
+pub struct Cache {
+    conn: Connection,
+}
+
+impl Cache {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    #[cfg(test)]
+    fn open_in_memory() -> Result<Self, Error> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    /// Whether `uid` (under `uidvalidity`) has already been moved out of
+    /// the inbox, so a restart after a crash doesn't move it again.
+    pub fn is_moved(&self, account: &str, uidvalidity: u32, uid: u32) -> Result<bool, Error> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT 1 FROM moved WHERE account = ?1 AND uidvalidity = ?2 AND uid = ?3",
+                params![account, uidvalidity, uid],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    pub fn mark_moved(&self, account: &str, uidvalidity: u32, uid: u32) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO moved (account, uidvalidity, uid) VALUES (?1, ?2, ?3)",
+            params![account, uidvalidity, uid],
+        )?;
+        Ok(())
+    }
+
+    /// Persists a notification before it's sent, returning its id so
+    /// `complete_notification` can clear it once delivery succeeds.
+    pub fn enqueue_notification(&self, account: &str, mailboxes: &[String]) -> Result<i64, Error> {
+        self.conn.execute(
+            "INSERT INTO pending_notifications (account, mailboxes) VALUES (?1, ?2)",
+            params![account, mailboxes.join(",")],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn complete_notification(&self, id: i64) -> Result<(), Error> {
+        self.conn
+            .execute("DELETE FROM pending_notifications WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Notifications that were enqueued but never confirmed delivered,
+    /// replayed on the next `sort_mail` call after a crash or failed send.
+    pub fn pending_notifications(&self, account: &str) -> Result<Vec<(i64, Vec<String>)>, Error> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT id, mailboxes FROM pending_notifications WHERE account = ?1")?;
+        let rows = statement
+            .query_map(params![account], |row| {
+                let id: i64 = row.get(0)?;
+                let mailboxes: String = row.get(1)?;
+                Ok((id, mailboxes.split(',').map(str::to_string).collect()))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unmarked_uid_is_not_moved() {
+        let cache = Cache::open_in_memory().unwrap();
+        assert!(!cache.is_moved("a@example.com", 1, 42).unwrap());
+    }
+
+    #[test]
+    fn marking_moved_is_idempotent() {
+        let cache = Cache::open_in_memory().unwrap();
+        cache.mark_moved("a@example.com", 1, 42).unwrap();
+        cache.mark_moved("a@example.com", 1, 42).unwrap();
+        assert!(cache.is_moved("a@example.com", 1, 42).unwrap());
+    }
+
+    #[test]
+    fn moved_state_does_not_cross_uidvalidity() {
+        let cache = Cache::open_in_memory().unwrap();
+        cache.mark_moved("a@example.com", 1, 42).unwrap();
+        assert!(!cache.is_moved("a@example.com", 2, 42).unwrap());
+    }
+
+    #[test]
+    fn enqueued_notification_is_pending_until_completed() {
+        let cache = Cache::open_in_memory().unwrap();
+        let id = cache
+            .enqueue_notification("a@example.com", &["inbox.foo".to_string()])
+            .unwrap();
+        assert_eq!(
+            cache.pending_notifications("a@example.com").unwrap(),
+            vec![(id, vec!["inbox.foo".to_string()])]
+        );
+        cache.complete_notification(id).unwrap();
+        assert!(cache.pending_notifications("a@example.com").unwrap().is_empty());
+    }
+}
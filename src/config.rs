@@ -1,19 +1,88 @@
+use crate::rules::Rule;
 use pushover_rs::{MessageBuilder, send_pushover_request};
 use serde::Deserialize;
-use std::collections::HashSet;
+use std::{collections::HashSet, path::PathBuf};
 
+/// Top-level configuration: one independently supervised mailbox per entry
+/// in `accounts`, so a single daemon can sort several mailboxes at once.
 #[derive(Deserialize, Debug)]
 pub struct Config {
+    pub accounts: Vec<Account>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Account {
     pub imap: Imap,
     pub pushover: Pushover,
+    pub managesieve: Option<ManageSieve>,
+    /// Header-based routing rules, evaluated in order. Defaults to the
+    /// classic Protonmail `X-Pm-Original-To` rule when omitted.
+    #[serde(default = "Account::default_rules")]
+    pub rules: Vec<Rule>,
+    /// Mailbox messages are routed to when no rule matches.
+    #[serde(default)]
+    pub default_mailbox: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+impl Account {
+    /// Directory used to persist IMAP synchronization state (CONDSTORE
+    /// cursors, one file per account). Defaults to `.mail-sort` in the
+    /// current directory if not set.
+    pub fn state_dir(&self) -> PathBuf {
+        self.imap
+            .state_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(".mail-sort"))
+    }
+
+    /// Path to the SQLite cache of moved UIDs and pending notifications,
+    /// kept alongside the CONDSTORE state in `state_dir`.
+    pub fn cache_path(&self) -> PathBuf {
+        self.state_dir().join(format!("{}.sqlite3", self.imap.email))
+    }
+
+    fn default_rules() -> Vec<Rule> {
+        vec![Rule::proton_default()]
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct Imap {
     pub server: String,
     pub port: u16,
     pub email: String,
     pub password: String,
+    /// Where to persist the CONDSTORE sync cursor for this account.
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ManageSieve {
+    pub server: String,
+    pub port: u16,
+    /// Falls back to the IMAP email/password when not set, since most
+    /// servers accept the same credentials for both protocols.
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "ManageSieve::default_script_name")]
+    pub script_name: String,
+}
+
+impl ManageSieve {
+    fn default_script_name() -> String {
+        "mail-sort".to_string()
+    }
+
+    /// Credentials to authenticate with, falling back to the IMAP account's.
+    pub fn credentials<'a>(&'a self, imap: &'a Imap) -> (&'a str, &'a str) {
+        (
+            self.email.as_deref().unwrap_or(&imap.email),
+            self.password.as_deref().unwrap_or(&imap.password),
+        )
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -23,10 +92,14 @@ pub struct Pushover {
     mailboxes: HashSet<String>,
 }
 
+#[derive(Debug, thiserror::Error)]
+#[error("Pushover request failed")]
+pub struct NotifyError;
+
 impl Pushover {
-    pub async fn notify(&self, mailboxes: HashSet<String>) {
+    pub async fn notify(&self, mailboxes: HashSet<String>) -> Result<(), NotifyError> {
         if mailboxes.is_empty() {
-            return;
+            return Ok(());
         }
         let intersection = self.mailboxes.intersection(&mailboxes);
         // Send a silent notification, if no subscribed mailboxes exist
@@ -36,7 +109,9 @@ impl Pushover {
         let message = MessageBuilder::new(&self.user, &self.token, &text)
             .set_priority(priority)
             .build();
-        // TODO: Log delivery failure
-        let _ = send_pushover_request(message).await;
+        send_pushover_request(message)
+            .await
+            .map(|_| ())
+            .map_err(|_| NotifyError)
     }
 }
@@ -1,24 +1,43 @@
-use clap::Parser;
-use config::Config;
+use clap::{Parser, Subcommand};
+use config::{Account, Config};
 use imap::{
     self, ClientBuilder, Session, extensions::idle::WaitOutcome, types::UnsolicitedResponse,
 };
-use mailparse::{self, MailHeaderMap, addrparse, parse_headers};
+use mailparse::{self, MailHeaderMap, parse_headers};
 use std::{
     collections::{HashMap, HashSet},
     fs::read_to_string,
     io::{self, Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     time::Duration,
 };
 
+mod backoff;
+mod cache;
 mod config;
+mod qresync;
+mod rules;
+mod sieve;
+mod state;
+
+use backoff::Backoff;
+use cache::Cache;
+use state::SyncState;
 
 #[derive(Parser)]
 #[command(about = "Sort emails into mailboxes based on recipient addresses")]
 struct Args {
     #[arg(long, help = "Path to the config file")]
     config: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compile the routing rules into a Sieve script and upload it, so the
+    /// server files messages even when this tool isn't connected
+    InstallSieve,
 }
 
 type Sorted = HashMap<String, HashSet<u32>>;
@@ -26,16 +45,100 @@ type Sorted = HashMap<String, HashSet<u32>>;
 #[tokio::main]
 async fn main() -> Result<(), Err> {
     let args = Args::parse();
-    let config: Config = toml::from_str(&read_to_string(args.config)?)?;
-    let mut session = ClientBuilder::new(&config.imap.server, config.imap.port)
+    let config: Config = toml::from_str(&read_to_string(&args.config)?)?;
+
+    if let Some(Command::InstallSieve) = args.command {
+        // One account's install-sieve failure (e.g. missing managesieve
+        // config, a transient IMAP error) shouldn't stop the rest from
+        // being installed.
+        let mut failed = false;
+        for account in &config.accounts {
+            if let Err(error) = install_sieve(account) {
+                eprintln!("[{}] Failed to install Sieve script: {error}", account.imap.email);
+                failed = true;
+            }
+        }
+        return if failed { Err(Err::InstallSieveFailed) } else { Ok(()) };
+    }
+
+    // Each account is supervised by its own task so several mailboxes can
+    // be sorted concurrently by one daemon. A panic in one account's task
+    // is logged and otherwise ignored, so it can't take down the others'
+    // independently supervised sessions.
+    let tasks: Vec<_> = config
+        .accounts
+        .into_iter()
+        .map(|account| {
+            let email = account.imap.email.clone();
+            (email, tokio::spawn(supervise(account)))
+        })
+        .collect();
+    for (email, task) in tasks {
+        if let Err(error) = task.await {
+            eprintln!("[{email}] Account task panicked ({error}), giving up on it");
+        }
+    }
+    Ok(())
+}
+
+/// Reconnects with backoff whenever `run_once` returns a connection error,
+/// so a dropped connection resyncs and resumes instead of ending the task.
+///
+/// Scope note: this gets the concurrency property the original request
+/// cared about — several mailboxes sorted at once, each independently
+/// supervised — but not by the literal means it described. The `imap`
+/// crate's `Session` is blocking end to end (its own `Read + Write`
+/// transport, no `Stream`/`Future` anywhere), and there's no maintained
+/// async IMAP crate to swap it for. Rewriting `sort_mail`/`sort_inbox`/
+/// `mailboxes` as async methods on a real async session would mean
+/// reimplementing the IMAP client this tool depends on, not just this
+/// module. Given that, each connection's connect/resync/idle loop runs
+/// synchronously on a blocking-pool thread via `spawn_blocking` instead;
+/// this function itself only schedules those attempts and backs off
+/// between them. Flagging this substitution explicitly rather than
+/// papering over it: if a true async backend is still wanted, it's a
+/// separate, much larger undertaking than this function.
+async fn supervise(account: Account) {
+    let state_dir = account.state_dir();
+    let mut backoff = Backoff::default();
+    loop {
+        let result = {
+            let account = account.clone();
+            let state_dir = state_dir.clone();
+            tokio::task::spawn_blocking(move || run_once(&account, &state_dir))
+                .await
+                .expect("account thread panicked")
+        };
+        match result {
+            Ok(()) => break,
+            Err(error) => {
+                eprintln!(
+                    "[{}] Connection lost ({error}), reconnecting...",
+                    account.imap.email
+                );
+                tokio::time::sleep(backoff.next()).await;
+            }
+        }
+    }
+}
+
+/// Connects, resyncs via QRESYNC and idles until the connection drops.
+/// Never logs out or returns `Ok(())`: the idle loop only ends on an error,
+/// which bubbles up so `supervise` can retry with backoff.
+fn run_once(account: &Account, state_dir: &Path) -> Result<(), Err> {
+    let mut session = ClientBuilder::new(&account.imap.server, account.imap.port)
         .connect()?
-        .login(&config.imap.email, &config.imap.password)
+        .login(&account.imap.email, &account.imap.password)
         .map_err(|e| e.0)?;
     session.debug = true; // Remove
-    session.select("INBOX")?;
+
+    let mut sync_state = SyncState::load(state_dir, &account.imap.email)?;
+    let resync = qresync::select_with_resync(&mut session, &mut sync_state)?;
+    let cache = Cache::open(&account.cache_path())?;
 
     // Do initial mail sort
-    sort_mail(&mut session, &config.pushover)?;
+    sort_mail(&mut session, account, &mut sync_state, &cache, &resync)?;
+    sync_state.save(state_dir, &account.imap.email)?;
 
     loop {
         // Idle and wait for `Exists` messages which indicate mail count change
@@ -60,26 +163,103 @@ async fn main() -> Result<(), Err> {
                 }
                 WaitOutcome::MailboxChanged => {
                     println!("Mailbox Changed");
-                    sort_mail(&mut session, &config.pushover)?
+                    let resync = qresync::select_with_resync(&mut session, &mut sync_state)?;
+                    sort_mail(&mut session, account, &mut sync_state, &cache, &resync)?;
+                    sync_state.save(state_dir, &account.imap.email)?;
                 }
             },
-            Result::Err(error) => {
-                dbg!(error);
-                break;
-            }
+            Result::Err(error) => return Err(error.into()),
         }
     }
+}
+
+/// Compiles the current mailbox layout into a Sieve script and uploads it
+/// over ManageSieve, so the server files messages without this tool running.
+fn install_sieve(account: &Account) -> Result<(), Err> {
+    let managesieve = account.managesieve.as_ref().ok_or(Err::MissingManageSieve)?;
+
+    let mut session = ClientBuilder::new(&account.imap.server, account.imap.port)
+        .connect()?
+        .login(&account.imap.email, &account.imap.password)
+        .map_err(|e| e.0)?;
+    let mappings = discover_mappings(&mut session, &account.rules)?;
     session.logout().expect("logout");
+
+    let script = sieve::generate_script(&mappings);
+    let (email, password) = managesieve.credentials(&account.imap);
+    let mut client = sieve::ManageSieveClient::connect(&managesieve.server, managesieve.port)?;
+    client.authenticate(email, password)?;
+    client.put_script(&managesieve.script_name, &script)?;
+    client.set_active(&managesieve.script_name)?;
+    println!("Installed Sieve script {:?}", managesieve.script_name);
     Ok(())
 }
 
+/// Discovers concrete `(header, value, mailbox)` mappings by checking every
+/// message in each existing non-inbox mailbox for one bearing one of
+/// `rules`' configured headers. This lets the generated Sieve script match
+/// the same header/value pairs this tool's own rules already recognize,
+/// instead of assuming any particular mailbox naming scheme (which breaks
+/// as soon as a rule uses a different header, a regex capture, or a custom
+/// template). Scanning the whole mailbox, rather than just its first
+/// message, avoids silently dropping a mailbox whose oldest surviving
+/// message happens to be missing all of `rules`' headers.
+fn discover_mappings<T: Write + Read>(
+    session: &mut Session<T>,
+    rules: &[rules::Rule],
+) -> Result<Vec<(String, String, String)>, Err> {
+    let header_fields = rules::header_fields(rules);
+    let headers: Vec<&str> = rules.iter().map(|rule| rule.header.as_str()).collect();
+    let mut mappings = Vec::new();
+    for mailbox in mailboxes(session)? {
+        if mailbox.eq_ignore_ascii_case("INBOX") {
+            continue;
+        }
+        let mailbox_info = session.select(&mailbox)?;
+        if mailbox_info.exists == 0 {
+            continue;
+        }
+        let fetches =
+            session.fetch("1:*", &format!("(BODY.PEEK[HEADER.FIELDS ({header_fields})])"))?;
+        let mapping = fetches.iter().find_map(|fetch| {
+            let (message_headers, _) = parse_headers(fetch.header()?).ok()?;
+            headers.iter().find_map(|header| {
+                message_headers
+                    .get_first_value(header)
+                    .map(|value| (header.to_string(), value))
+            })
+        });
+        if let Some((header, value)) = mapping {
+            mappings.push((header, value, mailbox));
+        }
+    }
+    Ok(mappings)
+}
+
 /// Moves emails and creates mailboxes if required
 fn sort_mail<T: Write + Read>(
     session: &mut Session<T>,
-    pushover: &config::Pushover,
+    account: &Account,
+    sync_state: &mut SyncState,
+    cache: &Cache,
+    resync: &qresync::Resync,
 ) -> Result<(), Err> {
+    // Replay any notification that was enqueued but never confirmed
+    // delivered, e.g. because the process crashed or the last send failed.
+    replay_pending_notifications(&account.pushover, cache, &account.imap.email)?;
+
+    if !resync.vanished.is_empty() {
+        println!("Vanished (expunged since last sync): {:?}", resync.vanished);
+    }
+
     let existing = mailboxes(session)?;
-    let sorted = sort_inbox(session)?;
+    let sorted = sort_inbox(
+        session,
+        sync_state,
+        &account.rules,
+        account.default_mailbox.as_deref(),
+        resync,
+    )?;
     for (mailbox, ids) in sorted.iter() {
         if !existing.contains(mailbox) {
             session.create(mailbox)?;
@@ -88,21 +268,70 @@ fn sort_mail<T: Write + Read>(
             }
             println!("Created {}", mailbox);
         }
-        let ids_string = ids
+        // Skip UIDs a prior crash already moved but never got recorded in
+        // `sync_state`, so a restart doesn't attempt the same move twice.
+        let pending_uids: Vec<u32> = ids
+            .iter()
+            .copied()
+            .filter(|uid| {
+                !cache
+                    .is_moved(&account.imap.email, sync_state.uidvalidity, *uid)
+                    .unwrap_or(false)
+            })
+            .collect();
+        if pending_uids.is_empty() {
+            continue;
+        }
+        let ids_string = pending_uids
             .iter()
             .map(|id| id.to_string())
             .collect::<Vec<String>>()
             .join(",");
         session.uid_mv(&ids_string, mailbox)?;
+        for uid in &pending_uids {
+            cache.mark_moved(&account.imap.email, sync_state.uidvalidity, *uid)?;
+        }
         println!("Moved {}, to: {}", &ids_string, &mailbox);
     }
-    let pushover = pushover.clone();
-    tokio::spawn(async move {
-        pushover.notify(sorted.into_keys().collect()).await;
-    });
+
+    if !sorted.is_empty() {
+        let mailboxes: Vec<String> = sorted.into_keys().collect();
+        let id = cache.enqueue_notification(&account.imap.email, &mailboxes)?;
+        deliver_notification(&account.pushover, cache, id, mailboxes)?;
+    }
     Ok(())
 }
 
+/// Attempts each notification still sitting in the cache; on success it's
+/// removed, on failure it's left for the next `sort_mail` call to retry.
+fn replay_pending_notifications(
+    pushover: &config::Pushover,
+    cache: &Cache,
+    account_email: &str,
+) -> Result<(), Err> {
+    for (id, mailboxes) in cache.pending_notifications(account_email)? {
+        deliver_notification(pushover, cache, id, mailboxes)?;
+    }
+    Ok(())
+}
+
+fn deliver_notification(
+    pushover: &config::Pushover,
+    cache: &Cache,
+    id: i64,
+    mailboxes: Vec<String>,
+) -> Result<(), Err> {
+    let notification = pushover.notify(mailboxes.into_iter().collect());
+    let result = tokio::runtime::Handle::current().block_on(notification);
+    match result {
+        Ok(()) => Ok(cache.complete_notification(id)?),
+        Err(_) => {
+            println!("Notification {id} failed to deliver, will retry later");
+            Ok(())
+        }
+    }
+}
+
 // Returns all parent mailboxes "foo.bar.baz" -> "foo", "foo.bar", "foo.bar.baz"
 fn with_parents(mailbox: &str) -> impl Iterator<Item = String> + '_ {
     let parts: Vec<&str> = mailbox.split('.').collect();
@@ -118,47 +347,60 @@ fn mailboxes<T: Write + Read>(session: &mut Session<T>) -> Result<HashSet<String
         .collect())
 }
 
-fn sort_inbox<T: Write + Read>(session: &mut Session<T>) -> Result<Sorted, Err> {
-    // Fetch the headers that show actual delivery address
-    let fetches = session.uid_fetch("1:*", "BODY.PEEK[HEADER.FIELDS (X-PM-ORIGINAL-TO)]")?;
+fn sort_inbox<T: Write + Read>(
+    session: &mut Session<T>,
+    sync_state: &mut SyncState,
+    rules: &[rules::Rule],
+    default_mailbox: Option<&str>,
+    resync: &qresync::Resync,
+) -> Result<Sorted, Err> {
+    let header_fields = rules::header_fields(rules);
+    // QRESYNC already told us exactly which UIDs changed (new or
+    // flag-changed) since the cursor we resynced from, so fetch precisely
+    // those instead of re-deriving a range ourselves. Otherwise, with a
+    // valid cursor, fetch messages above the last processed UID that
+    // changed since the last processed HIGHESTMODSEQ; with no cursor at all,
+    // rescan the whole mailbox.
+    let (sequence, query) = if !resync.changed.is_empty() {
+        let uids = resync
+            .changed
+            .iter()
+            .map(u32::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        (uids, format!("BODY.PEEK[HEADER.FIELDS ({header_fields})]"))
+    } else if sync_state.is_synced() {
+        (
+            format!("{}:*", sync_state.last_uid + 1),
+            format!(
+                "(BODY.PEEK[HEADER.FIELDS ({header_fields})]) (CHANGEDSINCE {})",
+                sync_state.highest_modseq
+            ),
+        )
+    } else {
+        (
+            "1:*".to_string(),
+            format!("BODY.PEEK[HEADER.FIELDS ({header_fields})]"),
+        )
+    };
+
+    let fetches = session.uid_fetch(&sequence, &query)?;
     let mut sorted = Sorted::new();
     for fetch in fetches.iter() {
         let header_data = fetch.header().ok_or(Err::MissingHeader)?;
-        let (mail_header, _) = parse_headers(header_data)?;
+        let (headers, _) = parse_headers(header_data)?;
         let uid = fetch.uid.ok_or(Err::MissingUid)?;
-        let recipient = mail_header.get_first_value("X-Pm-Original-To");
-        println!("Found: {:?}", recipient);
-        if let Some(recipient_str) = recipient {
-            let address_list = addrparse(&recipient_str)?;
-            if let Some(address) = address_list.extract_single_info() {
-                let mailbox = mailbox_from(&address.addr);
-                let uids = sorted.entry(mailbox).or_insert(HashSet::default());
-                uids.insert(uid);
-            }
+        sync_state.last_uid = sync_state.last_uid.max(uid);
+        let mailbox = rules::route(rules, &headers, default_mailbox);
+        println!("Routed: {:?}", mailbox);
+        if let Some(mailbox) = mailbox {
+            let uids = sorted.entry(mailbox).or_insert(HashSet::default());
+            uids.insert(uid);
         }
     }
     Ok(sorted)
 }
 
-/// Converts email address in to a mailbox name
-fn mailbox_from(address: &str) -> String {
-    let mut mailbox_name = String::new();
-    let mut parts = address.splitn(2, "@");
-    let localpart = parts.next().expect("First part");
-    if let Some(domain) = parts.next() {
-        for char in domain.chars() {
-            if char == '.' {
-                mailbox_name.push('_');
-            } else {
-                mailbox_name.push(char);
-            }
-        }
-    }
-    mailbox_name.push('.');
-    mailbox_name.push_str(localpart);
-    mailbox_name.to_lowercase()
-}
-
 #[derive(Debug, thiserror::Error)]
 pub enum Err {
     #[error("Missing Header")]
@@ -173,20 +415,20 @@ pub enum Err {
     IO(#[from] io::Error),
     #[error("Toml error")]
     Toml(#[from] toml::de::Error),
+    #[error("ManageSieve is not configured")]
+    MissingManageSieve,
+    #[error("install-sieve failed for one or more accounts, see above")]
+    InstallSieveFailed,
+    #[error("Sieve: {0}")]
+    Sieve(#[from] sieve::Error),
+    #[error("Cache: {0}")]
+    Cache(#[from] cache::Error),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn address_to_mailbox() {
-        assert_eq!(
-            "example_com.auth.service",
-            &mailbox_from("auth.service@example.com")
-        );
-    }
-
     #[test]
     fn test_mailbox_hierarchy_nested() {
         let result: Vec<String> = with_parents("foo.bar.baz").collect();
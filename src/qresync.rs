@@ -0,0 +1,166 @@
+//! QRESYNC-based resynchronization (RFC 7162) for picking up exactly which
+//! messages appeared, changed or were expunged while disconnected, instead
+//! of re-scanning the whole mailbox on every reconnect.
+
+use crate::{Err, state::SyncState};
+use imap::Session;
+use std::io::{Read, Write};
+
+/// What the server told us changed since the cursor we resynced from: UIDs
+/// expunged (`VANISHED (EARLIER)`), and UIDs QRESYNC already reported as
+/// changed via the untagged `FETCH` responses it piggybacks onto `SELECT`.
+/// Both are only populated when a QRESYNC resync actually happened; a plain
+/// CONDSTORE or bare `SELECT` leaves both empty, and `sort_inbox` falls
+/// back to its own `CHANGEDSINCE`/full-scan fetch in that case.
+#[derive(Debug, Default, PartialEq)]
+pub struct Resync {
+    pub vanished: Vec<u32>,
+    pub changed: Vec<u32>,
+}
+
+/// Selects INBOX, requesting QRESYNC replay when both the server and a
+/// previously synced cursor support it (falling back to plain CONDSTORE
+/// otherwise), and folds the resulting UIDVALIDITY/HIGHESTMODSEQ/VANISHED
+/// data into `state`/the returned `Resync`.
+pub fn select_with_resync<T: Write + Read>(
+    session: &mut Session<T>,
+    state: &mut SyncState,
+) -> Result<Resync, Err> {
+    let capabilities = session.capabilities()?;
+    let use_qresync = capabilities.has_str("QRESYNC") && state.is_synced();
+    // Per RFC 7162 3.1.8, a server only includes HIGHESTMODSEQ in the SELECT
+    // response once CONDSTORE has been enabled for the session, so it must
+    // be requested explicitly whenever we're not already QRESYNC-ing.
+    let use_condstore = capabilities.has_str("CONDSTORE");
+    let command = if use_qresync {
+        format!(
+            "SELECT INBOX (QRESYNC ({} {}))",
+            state.uidvalidity, state.highest_modseq
+        )
+    } else if use_condstore {
+        "SELECT INBOX (CONDSTORE)".to_string()
+    } else {
+        "SELECT INBOX".to_string()
+    };
+    let raw = session.run_command_and_read_response(&command)?;
+    let response = String::from_utf8_lossy(&raw);
+
+    if let Some(uid_validity) = parse_u32_field(&response, "UIDVALIDITY") {
+        if uid_validity != state.uidvalidity {
+            // UIDs from a previous UIDVALIDITY are no longer comparable, so
+            // the cursor is meaningless: fall back to a full 1:* scan.
+            state.invalidate();
+            state.uidvalidity = uid_validity;
+        }
+    }
+    if let Some(highest_mod_seq) = parse_u64_field(&response, "HIGHESTMODSEQ") {
+        state.highest_modseq = highest_mod_seq;
+    }
+
+    // The VANISHED/FETCH replay is only meaningful when the server actually
+    // sent one, i.e. we asked for QRESYNC resync above.
+    if !use_qresync {
+        return Ok(Resync::default());
+    }
+    Ok(Resync {
+        vanished: parse_vanished(&response),
+        changed: parse_changed(&response),
+    })
+}
+
+fn parse_u32_field(response: &str, field: &str) -> Option<u32> {
+    parse_field(response, field).and_then(|s| s.parse().ok())
+}
+
+fn parse_u64_field(response: &str, field: &str) -> Option<u64> {
+    parse_field(response, field).and_then(|s| s.parse().ok())
+}
+
+/// Extracts the value out of a `* OK [FIELD 123]` style response line.
+fn parse_field<'a>(response: &'a str, field: &str) -> Option<&'a str> {
+    let after = response.split(field).nth(1)?;
+    after
+        .trim_start_matches(|c: char| !c.is_ascii_digit())
+        .split(|c: char| !c.is_ascii_digit())
+        .next()
+        .filter(|s| !s.is_empty())
+}
+
+/// Expands the UID set of a `* VANISHED (EARLIER) 1:5,9,12:14` response line
+/// into individual UIDs.
+fn parse_vanished(response: &str) -> Vec<u32> {
+    let mut uids = Vec::new();
+    for line in response.lines() {
+        let Some(set) = line.strip_prefix("* VANISHED (EARLIER) ") else {
+            continue;
+        };
+        for part in set.trim().split(',') {
+            match part.split_once(':') {
+                Some((start, end)) => {
+                    if let (Ok(start), Ok(end)) = (start.parse(), end.parse()) {
+                        uids.extend(start..=end);
+                    }
+                }
+                None => {
+                    if let Ok(uid) = part.parse() {
+                        uids.push(uid);
+                    }
+                }
+            }
+        }
+    }
+    uids
+}
+
+/// Extracts the UID out of each untagged `* <seq> FETCH (UID <uid> ...)`
+/// response line QRESYNC piggybacks onto `SELECT`, reporting the messages
+/// whose flags (or existence) changed since the requested HIGHESTMODSEQ.
+fn parse_changed(response: &str) -> Vec<u32> {
+    response
+        .lines()
+        .filter(|line| line.contains("FETCH ("))
+        .filter_map(|line| parse_u32_field(line, "UID"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_uidvalidity_and_modseq() {
+        let response = "* OK [UIDVALIDITY 1234567890] UIDs valid\r\n\
+             * OK [HIGHESTMODSEQ 42] Highest\r\n";
+        assert_eq!(parse_u32_field(response, "UIDVALIDITY"), Some(1234567890));
+        assert_eq!(parse_u64_field(response, "HIGHESTMODSEQ"), Some(42));
+    }
+
+    #[test]
+    fn missing_field_is_none() {
+        assert_eq!(parse_u32_field("* OK still here\r\n", "UIDVALIDITY"), None);
+    }
+
+    #[test]
+    fn parses_vanished_ranges_and_singletons() {
+        let response = "* VANISHED (EARLIER) 1:5,9,12:14\r\n";
+        assert_eq!(parse_vanished(response), vec![1, 2, 3, 4, 5, 9, 12, 13, 14]);
+    }
+
+    #[test]
+    fn missing_vanished_line_is_empty() {
+        assert_eq!(parse_vanished("* OK still here\r\n"), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn parses_changed_uids_from_fetch_replay() {
+        let response = "* 3 FETCH (UID 15 MODSEQ (42) FLAGS (\\Seen))\r\n\
+             * 5 FETCH (UID 22 MODSEQ (45) FLAGS ())\r\n";
+        assert_eq!(parse_changed(response), vec![15, 22]);
+    }
+
+    #[test]
+    fn non_fetch_lines_contribute_no_changed_uids() {
+        let response = "* OK [HIGHESTMODSEQ 42] Highest\r\n";
+        assert_eq!(parse_changed(response), Vec::<u32>::new());
+    }
+}
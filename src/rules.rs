@@ -0,0 +1,126 @@
+//! Header-based routing rules that turn a recipient address into a mailbox
+//! name. Rules are evaluated in order; the first one whose header is
+//! present and whose `match` (if any) matches wins.
+
+use mailparse::{MailHeader, MailHeaderMap, addrparse};
+use regex::Regex;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Rule {
+    /// Header to read the recipient address from, e.g. `X-Pm-Original-To`.
+    pub header: String,
+    /// Optional regex the extracted address must match; capture groups are
+    /// available in `mailbox` as `{1}`, `{2}`, ...
+    #[serde(default)]
+    pub r#match: Option<String>,
+    /// Mailbox name template. Supports the placeholders `{domain}` and
+    /// `{localpart}`, plus numbered capture groups from `match`.
+    pub mailbox: String,
+}
+
+impl Rule {
+    /// The classic Protonmail rule this tool originally hardcoded:
+    /// `X-Pm-Original-To`, routed to `{domain}.{localpart}`.
+    pub fn proton_default() -> Self {
+        Self {
+            header: "X-Pm-Original-To".to_string(),
+            r#match: None,
+            mailbox: "{domain}.{localpart}".to_string(),
+        }
+    }
+
+    fn route(&self, headers: &[MailHeader]) -> Option<String> {
+        let value = headers.get_first_value(&self.header)?;
+        let address = addrparse(&value).ok()?.extract_single_info()?.addr;
+        let (localpart, domain) = address.split_once('@').unwrap_or((&address, ""));
+
+        let mut mailbox = self.mailbox.clone();
+        if let Some(pattern) = &self.r#match {
+            let captures = Regex::new(pattern).ok()?.captures(&address)?;
+            for (i, group) in captures.iter().enumerate().skip(1) {
+                if let Some(group) = group {
+                    mailbox = mailbox.replace(&format!("{{{i}}}"), group.as_str());
+                }
+            }
+        }
+        mailbox = mailbox
+            .replace("{domain}", &domain.replace('.', "_"))
+            .replace("{localpart}", localpart);
+        Some(mailbox.to_lowercase())
+    }
+}
+
+/// Evaluates `rules` in order against `headers`, returning the first
+/// matching mailbox name, or `default_mailbox` if none match.
+pub fn route(rules: &[Rule], headers: &[MailHeader], default_mailbox: Option<&str>) -> Option<String> {
+    rules
+        .iter()
+        .find_map(|rule| rule.route(headers))
+        .or_else(|| default_mailbox.map(str::to_string))
+}
+
+/// The distinct header names `rules` read from, for building the IMAP
+/// `HEADER.FIELDS` fetch query.
+pub fn header_fields(rules: &[Rule]) -> String {
+    let mut fields: Vec<&str> = Vec::new();
+    for rule in rules {
+        let header = rule.header.as_str();
+        if !fields.iter().any(|field| field.eq_ignore_ascii_case(header)) {
+            fields.push(header);
+        }
+    }
+    fields.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailparse::parse_headers;
+
+    fn headers(raw: &str) -> Vec<MailHeader<'_>> {
+        parse_headers(raw.as_bytes()).unwrap().0
+    }
+
+    #[test]
+    fn proton_default_routes_by_domain_and_localpart() {
+        let rule = Rule::proton_default();
+        let headers = headers("X-Pm-Original-To: auth.service@example.com\r\n\r\n");
+        assert_eq!(
+            rule.route(&headers),
+            Some("example_com.auth.service".to_string())
+        );
+    }
+
+    #[test]
+    fn match_captures_are_substituted_into_mailbox() {
+        let rule = Rule {
+            header: "Delivered-To".to_string(),
+            r#match: Some(r"^(\w+)\+(\w+)@example\.com$".to_string()),
+            mailbox: "example.{2}".to_string(),
+        };
+        let headers = headers("Delivered-To: me+receipts@example.com\r\n\r\n");
+        assert_eq!(rule.route(&headers), Some("example.receipts".to_string()));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = vec![
+            Rule {
+                header: "X-Missing".to_string(),
+                r#match: None,
+                mailbox: "unused".to_string(),
+            },
+            Rule::proton_default(),
+        ];
+        let headers = headers("X-Pm-Original-To: a@b.com\r\n\r\n");
+        assert_eq!(route(&rules, &headers, None), Some("b_com.a".to_string()));
+    }
+
+    #[test]
+    fn falls_back_to_default_mailbox() {
+        let rules = vec![Rule::proton_default()];
+        let headers = headers("Subject: no routing header here\r\n\r\n");
+        assert_eq!(route(&rules, &headers, Some("catch_all")), Some("catch_all".to_string()));
+    }
+}
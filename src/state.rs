@@ -0,0 +1,77 @@
+//! Persisted CONDSTORE synchronization cursor, so `sort_inbox` can fetch only
+//! messages that changed since the last run instead of rescanning `1:*`.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Per-account IMAP synchronization cursor.
+///
+/// `uidvalidity` must be checked against the value returned by `SELECT` on
+/// every run: if it differs, UIDs are no longer comparable and the cursor
+/// must be discarded in favor of a full `1:*` scan.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    pub uidvalidity: u32,
+    pub highest_modseq: u64,
+    pub last_uid: u32,
+}
+
+impl SyncState {
+    /// Loads the state for `account`, returning the default (empty) cursor
+    /// if no state file has been written yet.
+    pub fn load(dir: &Path, account: &str) -> io::Result<Self> {
+        match fs::read_to_string(Self::path(dir, account)) {
+            Ok(contents) => Ok(toml::from_str(&contents).unwrap_or_default()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Persists the state for `account`, creating `dir` if required.
+    pub fn save(&self, dir: &Path, account: &str) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let contents = toml::to_string(self).expect("serialize sync state");
+        fs::write(Self::path(dir, account), contents)
+    }
+
+    /// Drops the cursor, forcing the next `sort_inbox` call to do a full scan.
+    pub fn invalidate(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Whether a cursor is available to fetch incrementally from.
+    pub fn is_synced(&self) -> bool {
+        self.highest_modseq > 0
+    }
+
+    fn path(dir: &Path, account: &str) -> PathBuf {
+        dir.join(format!("{account}.toml"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresh_state_is_not_synced() {
+        assert!(!SyncState::default().is_synced());
+    }
+
+    #[test]
+    fn invalidate_clears_cursor() {
+        let mut state = SyncState {
+            uidvalidity: 7,
+            highest_modseq: 42,
+            last_uid: 100,
+        };
+        state.invalidate();
+        assert!(!state.is_synced());
+        assert_eq!(state.uidvalidity, 0);
+        assert_eq!(state.last_uid, 0);
+    }
+}